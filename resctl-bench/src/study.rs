@@ -4,6 +4,7 @@ use anyhow::{bail, Result};
 use log::error;
 use num_traits::cast::AsPrimitive;
 use quantiles::ckms::CKMS;
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::fmt::Write;
 use util::*;
@@ -14,6 +15,9 @@ use rd_agent_intf::{IoLatReport, Report};
 pub trait Study {
     fn study(&mut self, rep: &Report) -> Result<()>;
     fn as_study_mut(&mut self) -> &mut dyn Study;
+    // Called once per report that `run_fallible` failed to read, so studies
+    // tracking report sequence numbers can still account for the gap.
+    fn skip(&mut self) {}
 }
 
 //
@@ -31,6 +35,29 @@ pub fn sel_factory_iolat(io_type: &str, pct: &str) -> impl Fn(&Report) -> Option
     }
 }
 
+// Linearly-interpolated quantile of an already-sorted slice.
+fn exact_quantile(sorted: &[f64], q: f64) -> f64 {
+    match sorted.len() {
+        0 => 0.0,
+        1 => sorted[0],
+        len => {
+            let pos = q * (len - 1) as f64;
+            let lo = pos.floor() as usize;
+            let hi = pos.ceil() as usize;
+            sorted[lo] + (sorted[hi] - sorted[lo]) * (pos - lo as f64)
+        }
+    }
+}
+
+// Outlier-resistant summary from `StudyMeanTrait::robust_result()`.
+#[derive(Clone, Debug)]
+pub struct RobustMean {
+    pub median: f64,
+    pub mad: f64,
+    pub trimmed_mean: f64,
+    pub nr_filtered: usize,
+}
+
 //
 // Calculate average, min and max.
 //
@@ -41,6 +68,7 @@ where
 {
     sel: F,
     data: Vec<f64>,
+    robust: bool,
 }
 
 impl<T, F> StudyMean<T, F>
@@ -49,7 +77,19 @@ where
     F: Fn(&Report) -> Option<T>,
 {
     pub fn new(sel: F) -> Self {
-        Self { sel, data: vec![] }
+        Self {
+            sel,
+            data: vec![],
+            robust: false,
+        }
+    }
+
+    pub fn new_robust(sel: F) -> Self {
+        Self {
+            sel,
+            data: vec![],
+            robust: true,
+        }
     }
 }
 
@@ -72,6 +112,7 @@ where
 
 pub trait StudyMeanTrait: Study {
     fn result(&self) -> (f64, f64, f64, f64);
+    fn robust_result(&self) -> Option<RobustMean>;
 }
 
 impl<T, F> StudyMeanTrait for StudyMean<T, F>
@@ -94,6 +135,47 @@ where
 
         (mean, stdev, min, max)
     }
+
+    fn robust_result(&self) -> Option<RobustMean> {
+        if !self.robust || self.data.is_empty() {
+            return None;
+        }
+
+        // Scale factor so MAD is consistent with stdev for normal data.
+        const MAD_NORMAL_CONSISTENCY: f64 = 1.4826;
+
+        let mut sorted = self.data.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let median = exact_quantile(&sorted, 0.5);
+        let mut abs_devs: Vec<f64> = sorted.iter().map(|v| (v - median).abs()).collect();
+        abs_devs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let mad = MAD_NORMAL_CONSISTENCY * exact_quantile(&abs_devs, 0.5);
+
+        let q1 = exact_quantile(&sorted, 0.25);
+        let q3 = exact_quantile(&sorted, 0.75);
+        let iqr = q3 - q1;
+        let (lo, hi) = (q1 - 1.5 * iqr, q3 + 1.5 * iqr);
+
+        let filtered: Vec<f64> = sorted
+            .iter()
+            .cloned()
+            .filter(|v| *v >= lo && *v <= hi)
+            .collect();
+        let nr_filtered = sorted.len() - filtered.len();
+        let trimmed_mean = if filtered.is_empty() {
+            median
+        } else {
+            statistical::mean(&filtered)
+        };
+
+        Some(RobustMean {
+            median,
+            mad,
+            trimmed_mean,
+            nr_filtered,
+        })
+    }
 }
 
 //
@@ -158,6 +240,277 @@ where
     }
 }
 
+//
+// Mergeable percentile sketch (t-digest).
+//
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Centroid {
+    pub mean: f64,
+    pub count: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TDigest {
+    delta: f64,
+    centroids: Vec<Centroid>,
+    count: f64,
+    min: f64,
+    max: f64,
+    unmerged: usize,
+}
+
+impl TDigest {
+    const DFL_DELTA: f64 = 100.0;
+    const COMPRESS_AFTER: usize = 500;
+
+    pub fn new(delta: Option<f64>) -> Self {
+        Self {
+            delta: delta.unwrap_or(Self::DFL_DELTA),
+            centroids: vec![],
+            count: 0.0,
+            min: std::f64::MAX,
+            max: std::f64::MIN,
+            unmerged: 0,
+        }
+    }
+
+    // Scale function k(q) = delta / (2*pi) * asin(2q-1).
+    fn scale_k(&self, q: f64) -> f64 {
+        self.delta / (2.0 * std::f64::consts::PI) * (2.0 * q - 1.0).asin()
+    }
+
+    // Whether `[lo, hi)` may grow by `extra` without its k-scale span
+    // exceeding 1.
+    fn fits_scale(&self, lo: f64, hi: f64, extra: f64) -> bool {
+        let q_lo = (lo / self.count).max(0.0).min(1.0);
+        let q_hi = ((hi + extra) / self.count).max(0.0).min(1.0);
+        self.scale_k(q_hi) - self.scale_k(q_lo) <= 1.0
+    }
+
+    pub fn insert(&mut self, x: f64) {
+        self.min = self.min.min(x);
+        self.max = self.max.max(x);
+        self.count += 1.0;
+
+        if self.centroids.is_empty() {
+            self.centroids.push(Centroid {
+                mean: x,
+                count: 1.0,
+            });
+            return;
+        }
+
+        let mut nearest = 0;
+        let mut nearest_dist = std::f64::MAX;
+        let mut nearest_cum = 0.0;
+        let mut cum = 0.0;
+        for (i, c) in self.centroids.iter().enumerate() {
+            let dist = (c.mean - x).abs();
+            if dist < nearest_dist {
+                nearest_dist = dist;
+                nearest = i;
+                nearest_cum = cum;
+            }
+            cum += c.count;
+        }
+
+        let c_count = self.centroids[nearest].count;
+        if self.fits_scale(nearest_cum, nearest_cum + c_count, 1.0) {
+            let c = &mut self.centroids[nearest];
+            c.mean += (x - c.mean) / (c.count + 1.0);
+            c.count += 1.0;
+        } else {
+            let pos = match self.centroids[nearest].mean.partial_cmp(&x).unwrap() {
+                std::cmp::Ordering::Less => nearest + 1,
+                _ => nearest,
+            };
+            self.centroids.insert(
+                pos,
+                Centroid {
+                    mean: x,
+                    count: 1.0,
+                },
+            );
+            self.unmerged += 1;
+        }
+
+        if self.unmerged >= Self::COMPRESS_AFTER {
+            self.compress();
+        }
+    }
+
+    // Re-sort and re-absorb the centroid list (largest first).
+    pub fn compress(&mut self) {
+        if self.centroids.len() < 2 {
+            self.unmerged = 0;
+            return;
+        }
+
+        let mut centroids = std::mem::take(&mut self.centroids);
+        centroids.sort_by(|a, b| b.count.partial_cmp(&a.count).unwrap());
+
+        let count = self.count;
+        let min = self.min;
+        let max = self.max;
+        *self = Self::new(Some(self.delta));
+        self.count = count;
+        self.min = min;
+        self.max = max;
+
+        for c in centroids {
+            self.insert_centroid(c);
+        }
+        self.unmerged = 0;
+    }
+
+    fn insert_centroid(&mut self, nc: Centroid) {
+        if self.centroids.is_empty() {
+            self.centroids.push(nc);
+            return;
+        }
+
+        let mut nearest = 0;
+        let mut nearest_dist = std::f64::MAX;
+        let mut nearest_cum = 0.0;
+        let mut cum = 0.0;
+        for (i, c) in self.centroids.iter().enumerate() {
+            let dist = (c.mean - nc.mean).abs();
+            if dist < nearest_dist {
+                nearest_dist = dist;
+                nearest = i;
+                nearest_cum = cum;
+            }
+            cum += c.count;
+        }
+
+        let c_count = self.centroids[nearest].count;
+        if self.fits_scale(nearest_cum, nearest_cum + c_count, nc.count) {
+            let c = &mut self.centroids[nearest];
+            c.mean += (nc.mean - c.mean) * nc.count / (c.count + nc.count);
+            c.count += nc.count;
+        } else {
+            let pos = match self.centroids[nearest].mean.partial_cmp(&nc.mean).unwrap() {
+                std::cmp::Ordering::Less => nearest + 1,
+                _ => nearest,
+            };
+            self.centroids.insert(pos, nc);
+        }
+    }
+
+    // Merge in another digest's centroids.
+    pub fn merge(&mut self, other: &TDigest) {
+        if other.count == 0.0 {
+            return;
+        }
+
+        self.count += other.count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        for c in other.centroids.iter() {
+            self.centroids.push(c.clone());
+        }
+        self.compress();
+    }
+
+    pub fn count(&self) -> f64 {
+        self.count
+    }
+
+    pub fn query(&self, q: f64) -> f64 {
+        if self.centroids.is_empty() {
+            return 0.0;
+        }
+        if self.centroids.len() == 1 {
+            return self.centroids[0].mean;
+        }
+
+        let target = q * self.count;
+        let mut cum = 0.0;
+        for i in 0..self.centroids.len() {
+            let c = &self.centroids[i];
+            let next_cum = cum + c.count;
+            if target <= next_cum || i == self.centroids.len() - 1 {
+                if i == 0 {
+                    let frac = (target / next_cum).min(1.0).max(0.0);
+                    return self.min + (c.mean - self.min) * frac;
+                }
+                let prev = &self.centroids[i - 1];
+                let prev_cum = cum;
+                let frac = ((target - prev_cum) / (next_cum - prev_cum))
+                    .min(1.0)
+                    .max(0.0);
+                return prev.mean + (c.mean - prev.mean) * frac;
+            }
+            cum = next_cum;
+        }
+
+        self.max
+    }
+}
+
+pub struct StudyTDigest<T, F>
+where
+    T: AsPrimitive<f64>,
+    F: Fn(&Report) -> Option<T>,
+{
+    sel: F,
+    digest: TDigest,
+}
+
+impl<T, F> StudyTDigest<T, F>
+where
+    T: AsPrimitive<f64>,
+    F: Fn(&Report) -> Option<T>,
+{
+    pub fn new(sel: F, delta: Option<f64>) -> Self {
+        Self {
+            sel,
+            digest: TDigest::new(delta),
+        }
+    }
+}
+
+impl<T, F> Study for StudyTDigest<T, F>
+where
+    T: AsPrimitive<f64>,
+    F: Fn(&Report) -> Option<T>,
+{
+    fn study(&mut self, rep: &Report) -> Result<()> {
+        if let Some(v) = (self.sel)(rep) {
+            self.digest.insert(v.as_());
+        }
+        Ok(())
+    }
+
+    fn as_study_mut(&mut self) -> &mut dyn Study {
+        self
+    }
+}
+
+pub trait StudyTDigestTrait: Study {
+    fn result(&self, pcts: &[&str]) -> BTreeMap<String, f64>;
+    fn digest(&self) -> &TDigest;
+}
+
+impl<T, F> StudyTDigestTrait for StudyTDigest<T, F>
+where
+    T: AsPrimitive<f64>,
+    F: Fn(&Report) -> Option<T>,
+{
+    fn result(&self, pcts: &[&str]) -> BTreeMap<String, f64> {
+        pcts.iter()
+            .map(|pct| {
+                let pctf = pct.parse::<f64>().unwrap() / 100.0;
+                (pct.to_string(), self.digest.query(pctf))
+            })
+            .collect()
+    }
+
+    fn digest(&self) -> &TDigest {
+        &self.digest
+    }
+}
+
 //
 // Calculate mean and percentiles.
 //
@@ -181,6 +534,13 @@ where
             study_mean: StudyMean::<T, F>::new(sel),
         }
     }
+
+    pub fn new_robust(sel: F, error: Option<f64>) -> Self {
+        Self {
+            study_pcts: StudyPcts::<T, F>::new(sel.clone(), error),
+            study_mean: StudyMean::<T, F>::new_robust(sel),
+        }
+    }
 }
 
 impl<T, F> Study for StudyMeanPcts<T, F>
@@ -199,6 +559,7 @@ where
 
 pub trait StudyMeanPctsTrait: Study {
     fn result(&self, pcts: &[&str]) -> (f64, f64, BTreeMap<String, f64>);
+    fn robust_result(&self) -> Option<RobustMean>;
 }
 
 impl<T, F> StudyMeanPctsTrait for StudyMeanPcts<T, F>
@@ -211,6 +572,10 @@ where
         let pcts = self.study_pcts.result(pcts);
         (mean, stdev, pcts)
     }
+
+    fn robust_result(&self) -> Option<RobustMean> {
+        self.study_mean.robust_result()
+    }
 }
 
 //
@@ -242,6 +607,21 @@ impl StudyIoLatPcts {
         }
     }
 
+    pub fn new_robust(io_type: &str, error: Option<f64>) -> Self {
+        Self {
+            io_type: io_type.to_string(),
+            studies: Self::LAT_PCTS
+                .iter()
+                .map(|pct| {
+                    Box::new(StudyMeanPcts::new_robust(
+                        sel_factory_iolat(io_type, pct),
+                        error,
+                    )) as Box<dyn StudyMeanPctsTrait>
+                })
+                .collect(),
+        }
+    }
+
     pub fn studies(&mut self) -> Vec<&mut dyn Study> {
         self.studies
             .iter_mut()
@@ -259,6 +639,12 @@ impl StudyIoLatPcts {
             let (mean, stdev, mut pcts) = study.result(&time_pcts.unwrap_or(&Self::TIME_PCTS));
             pcts.insert("mean".to_string(), mean);
             pcts.insert("stdev".to_string(), stdev);
+            if let Some(robust) = study.robust_result() {
+                pcts.insert("median".to_string(), robust.median);
+                pcts.insert("mad".to_string(), robust.mad);
+                pcts.insert("trimmed".to_string(), robust.trimmed_mean);
+                pcts.insert("nrfilt".to_string(), robust.nr_filtered as f64);
+            }
             result.insert(lat_pct.to_string(), pcts);
         }
 
@@ -278,22 +664,32 @@ impl StudyIoLatPcts {
         result: &BTreeMap<String, BTreeMap<String, f64>>,
         time_pcts: Option<&[&str]>,
     ) {
-        let time_pcts = time_pcts
-            .unwrap_or(&Self::TIME_FORMAT_PCTS)
-            .iter()
-            .chain(Some("cum").iter())
-            .chain(Some("mean").iter())
-            .chain(Some("stdev").iter());
+        // Robust columns are only present in robust mode.
+        let robust = result
+            .values()
+            .next()
+            .map_or(false, |v| v.contains_key("median"));
+
+        let mut cols: Vec<&str> = time_pcts.unwrap_or(&Self::TIME_FORMAT_PCTS).to_vec();
+        cols.push("cum");
+        cols.push("mean");
+        cols.push("stdev");
+        if robust {
+            cols.push("median");
+            cols.push("mad");
+            cols.push("trimmed");
+            cols.push("nrfilt");
+        }
+
         write!(out, "       ").unwrap();
 
-        let widths: Vec<usize> = time_pcts
-            .clone()
-            .map(|pct| (pct.len() + 1).max(5))
-            .collect();
+        let widths: Vec<usize> = cols.iter().map(|pct| (pct.len() + 1).max(5)).collect();
 
         let fmt_pct = |pct: &str| -> String {
             match pct {
-                "cum" | "mean" | "stdev" => pct.to_string(),
+                "cum" | "mean" | "stdev" | "median" | "mad" | "trimmed" | "nrfilt" => {
+                    pct.to_string()
+                }
                 pct => {
                     let pctf = pct.parse::<f64>().unwrap();
                     if pctf == 0.0 {
@@ -307,26 +703,240 @@ impl StudyIoLatPcts {
             }
         };
 
-        for (pct, width) in time_pcts.clone().zip(widths.iter()) {
-            write!(out, " {:>1$}", &fmt_pct(*pct), width).unwrap();
+        for (pct, width) in cols.iter().zip(widths.iter()) {
+            write!(out, " {:>1$}", &fmt_pct(pct), width).unwrap();
         }
 
         for lat_pct in Self::LAT_PCTS.iter() {
             write!(out, "\n{:<7}", &fmt_pct(*lat_pct)).unwrap();
-            for (time_pct, width) in time_pcts.clone().zip(widths.iter()) {
-                write!(
-                    out,
-                    " {:>1$}",
-                    &format_duration(result[*lat_pct][*time_pct]),
-                    width
-                )
-                .unwrap();
+            for (pct, width) in cols.iter().zip(widths.iter()) {
+                let v = result[*lat_pct][*pct];
+                let formatted = if *pct == "nrfilt" {
+                    format!("{}", v as u64)
+                } else {
+                    format_duration(v)
+                };
+                write!(out, " {:>1$}", &formatted, width).unwrap();
             }
         }
         writeln!(out, "").unwrap();
     }
 }
 
+//
+// Detect the stable trailing window of a selector's time series.
+//
+pub struct StudySteadyState<F>
+where
+    F: Fn(&Report) -> Option<f64>,
+{
+    sel: F,
+    start: u64,
+    window: usize,
+    k: f64,
+    next_seq: u64,
+    // Sequence number paired with each sample, since `sel` returning
+    // `None` (e.g. no I/O yet during warmup) would otherwise silently
+    // shift a plain index/start offset away from the real report it
+    // came from.
+    seqs: Vec<u64>,
+    data: Vec<f64>,
+}
+
+impl<F> StudySteadyState<F>
+where
+    F: Fn(&Report) -> Option<f64>,
+{
+    const DFL_WINDOW: usize = 30;
+    const DFL_K: f64 = 3.0;
+
+    // `start` is the sequence number of the first report in the range, i.e.
+    // the `start` passed to `Studies::run_fallible()`, whether or not that
+    // report could be read.
+    pub fn new(sel: F, start: u64, window: Option<usize>, k: Option<f64>) -> Self {
+        Self {
+            sel,
+            start,
+            window: window.unwrap_or(Self::DFL_WINDOW),
+            k: k.unwrap_or(Self::DFL_K),
+            next_seq: start,
+            seqs: vec![],
+            data: vec![],
+        }
+    }
+}
+
+impl<F> Study for StudySteadyState<F>
+where
+    F: Fn(&Report) -> Option<f64>,
+{
+    fn study(&mut self, rep: &Report) -> Result<()> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        if let Some(v) = (self.sel)(rep) {
+            self.seqs.push(seq);
+            self.data.push(v);
+        }
+        Ok(())
+    }
+
+    fn as_study_mut(&mut self) -> &mut dyn Study {
+        self
+    }
+
+    fn skip(&mut self) {
+        self.next_seq += 1;
+    }
+}
+
+pub trait StudySteadyStateTrait: Study {
+    fn result(&self) -> (u64, u64);
+}
+
+impl<F> StudySteadyStateTrait for StudySteadyState<F>
+where
+    F: Fn(&Report) -> Option<f64>,
+{
+    // Online sliding-window CUSUM-style test: a change point is flagged at
+    // index `i` when the mean of the trailing window `[i-W, i)` differs
+    // from the mean of the following window `[i, i+W)` by more than `k`
+    // standard errors. Returns the boundaries of the largest trailing
+    // region with no flagged change point.
+    fn result(&self) -> (u64, u64) {
+        let n = self.data.len();
+        let end = self.next_seq;
+
+        if n == 0 {
+            return (self.start, self.start);
+        }
+        if n < 2 * self.window {
+            return (self.start, end);
+        }
+
+        let mut last_break = self.start;
+        for i in self.window..=(n - self.window) {
+            let left = &self.data[i - self.window..i];
+            let right = &self.data[i..i + self.window];
+
+            let lmean = statistical::mean(left);
+            let rmean = statistical::mean(right);
+            let lsd = statistical::standard_deviation(left, Some(lmean));
+            let rsd = statistical::standard_deviation(right, Some(rmean));
+            let se = ((lsd * lsd + rsd * rsd) / self.window as f64).sqrt();
+
+            if se > 0.0 && (lmean - rmean).abs() > self.k * se {
+                last_break = self.seqs[i];
+            }
+        }
+
+        (last_break, end)
+    }
+}
+
+// Pearson's r and the least-squares line `y = slope * x + intercept`.
+#[derive(Clone, Copy, Debug)]
+pub struct CorrelationResult {
+    pub r: f64,
+    pub slope: f64,
+    pub intercept: f64,
+    pub n: u64,
+}
+
+//
+// Correlate two selectors over the same report stream.
+//
+pub struct StudyCorrelation<FX, FY>
+where
+    FX: Fn(&Report) -> Option<f64>,
+    FY: Fn(&Report) -> Option<f64>,
+{
+    x_sel: FX,
+    y_sel: FY,
+    n: u64,
+    sum_x: f64,
+    sum_y: f64,
+    sum_xy: f64,
+    sum_x2: f64,
+    sum_y2: f64,
+}
+
+impl<FX, FY> StudyCorrelation<FX, FY>
+where
+    FX: Fn(&Report) -> Option<f64>,
+    FY: Fn(&Report) -> Option<f64>,
+{
+    pub fn new(x_sel: FX, y_sel: FY) -> Self {
+        Self {
+            x_sel,
+            y_sel,
+            n: 0,
+            sum_x: 0.0,
+            sum_y: 0.0,
+            sum_xy: 0.0,
+            sum_x2: 0.0,
+            sum_y2: 0.0,
+        }
+    }
+}
+
+impl<FX, FY> Study for StudyCorrelation<FX, FY>
+where
+    FX: Fn(&Report) -> Option<f64>,
+    FY: Fn(&Report) -> Option<f64>,
+{
+    fn study(&mut self, rep: &Report) -> Result<()> {
+        if let (Some(x), Some(y)) = ((self.x_sel)(rep), (self.y_sel)(rep)) {
+            self.n += 1;
+            self.sum_x += x;
+            self.sum_y += y;
+            self.sum_xy += x * y;
+            self.sum_x2 += x * x;
+            self.sum_y2 += y * y;
+        }
+        Ok(())
+    }
+
+    fn as_study_mut(&mut self) -> &mut dyn Study {
+        self
+    }
+}
+
+pub trait StudyCorrelationTrait: Study {
+    fn result(&self) -> CorrelationResult;
+}
+
+impl<FX, FY> StudyCorrelationTrait for StudyCorrelation<FX, FY>
+where
+    FX: Fn(&Report) -> Option<f64>,
+    FY: Fn(&Report) -> Option<f64>,
+{
+    fn result(&self) -> CorrelationResult {
+        let n = self.n as f64;
+        let cov = n * self.sum_xy - self.sum_x * self.sum_y;
+        let var_x = n * self.sum_x2 - self.sum_x * self.sum_x;
+        let var_y = n * self.sum_y2 - self.sum_y * self.sum_y;
+
+        let r = if var_x > 0.0 && var_y > 0.0 {
+            cov / (var_x.sqrt() * var_y.sqrt())
+        } else {
+            0.0
+        };
+        let slope = if var_x > 0.0 { cov / var_x } else { 0.0 };
+        let intercept = if self.n > 0 {
+            (self.sum_y - slope * self.sum_x) / n
+        } else {
+            0.0
+        };
+
+        CorrelationResult {
+            r,
+            slope,
+            intercept,
+            n: self.n,
+        }
+    }
+}
+
 //
 // Study execution interface.
 //
@@ -359,7 +969,12 @@ impl<'a> Studies<'a> {
                         study.study(&rep)?;
                     }
                 }
-                Err(_) => nr_missed += 1,
+                Err(_) => {
+                    nr_missed += 1;
+                    for study in self.studies.iter_mut() {
+                        study.skip();
+                    }
+                }
             }
         }
 